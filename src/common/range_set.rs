@@ -67,17 +67,68 @@ impl <'a> RangeSet {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    /// Returns whether `pos` falls inside a stored range.
+    pub fn contains(&self, pos: Seq32) -> bool {
+        self.pred(pos).map_or(false, |(_, end)| end > pos)
+    }
+
+    /// Returns whether any stored range overlaps `[start, end)`.
+    pub fn intersects(&self, start: Seq32, end: Seq32) -> bool {
+        if end <= start {
+            return false;
+        }
+        match self.pred(start) {
+            Some((_, p_end)) if p_end > start => true,
+            _ => self.succ(start).map_or(false, |(s_start, _)| s_start < end),
+        }
+    }
+
+    /// Walks the holes in `[from, to)`: the leading gap before the first
+    /// stored range, the gaps between consecutive stored ranges, and the
+    /// trailing gap after the last one. This is what a receiver needs to
+    /// turn its set of received ranges into SACK/NACK blocks.
+    pub fn gaps(&self, from: Seq32, to: Seq32) -> GapIter<'_> {
+        GapIter { set: self, cursor: from, to }
+    }
 }
 
-pub struct RangeSetIter<I: Iterator> {
-    iter: I,
+pub struct GapIter<'a> {
+    set: &'a RangeSet,
+    cursor: Seq32,
+    to: Seq32,
 }
 
-impl<I: Iterator> Iterator for RangeSetIter<I> {
-    type Item = <I as Iterator>::Item;
+impl<'a> Iterator for GapIter<'a> {
+    type Item = (Seq32, Seq32);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next()
+        loop {
+            if self.cursor >= self.to {
+                return None;
+            }
+
+            // skip over any stored range the cursor currently sits inside
+            if let Some((_, p_end)) = self.set.pred(self.cursor) {
+                if p_end > self.cursor {
+                    self.cursor = p_end;
+                    continue;
+                }
+            }
+
+            let gap_end = match self.set.succ(self.cursor) {
+                Some((s_start, _)) if s_start < self.to => s_start,
+                _ => self.to,
+            };
+            let gap_start = self.cursor;
+            self.cursor = gap_end;
+
+            return if gap_end > gap_start {
+                Some((gap_start, gap_end))
+            } else {
+                None
+            };
+        }
     }
 }
 
@@ -102,4 +153,26 @@ mod tests {
         assert_eq!(range_set.len(), 1);
         assert_eq!(range_set.insert(Seq32::from(0), Seq32::from(4294967280)), false);
     }
+
+    #[test]
+    fn range_set_gaps_test() {
+        let mut range_set = RangeSet::new();
+        range_set.insert(Seq32::from(100), Seq32::from(200));
+        range_set.insert(Seq32::from(250), Seq32::from(300));
+
+        let gaps: Vec<_> = range_set.gaps(Seq32::from(0), Seq32::from(400)).collect();
+        assert_eq!(gaps, vec![
+            (Seq32::from(0), Seq32::from(100)),
+            (Seq32::from(200), Seq32::from(250)),
+            (Seq32::from(300), Seq32::from(400)),
+        ]);
+
+        let gaps: Vec<_> = range_set.gaps(Seq32::from(150), Seq32::from(260)).collect();
+        assert_eq!(gaps, vec![(Seq32::from(200), Seq32::from(250))]);
+
+        assert_eq!(range_set.contains(Seq32::from(150)), true);
+        assert_eq!(range_set.contains(Seq32::from(220)), false);
+        assert_eq!(range_set.intersects(Seq32::from(190), Seq32::from(260)), true);
+        assert_eq!(range_set.intersects(Seq32::from(200), Seq32::from(250)), false);
+    }
 }
\ No newline at end of file
@@ -1,5 +1,7 @@
+use std::cell::RefCell;
 use std::collections::VecDeque;
-use bytes::{Bytes, BytesMut};
+use std::rc::Rc;
+use bytes::{Buf, Bytes, BytesMut};
 
 use crate::common::seq32::Seq32;
 use crate::common::range_set::RangeSet;
@@ -9,20 +11,97 @@ const RBUF_BLOCK_BIT: u32 = 17;
 const RBUF_BLOCK_SIZE: u32 = 1 << RBUF_BLOCK_BIT; // 128KB
 const RBUF_BLOCK_MASK: u32 = RBUF_BLOCK_SIZE - 1;
 
+/// A pool of recycled, already-allocated `RBUF_BLOCK_SIZE` blocks. `write`
+/// pulls from it before falling back to a fresh zeroed allocation, and
+/// `consume` returns freed blocks to it, so a steady stream of data stops
+/// churning 128KB allocations. Bounded by `max_blocks` so idle connections
+/// still give their memory back. A single pool can be shared (via `Rc`)
+/// across the `RecvBuffer`s of a multi-connection server.
+pub struct BlockPool {
+    max_blocks: usize,
+    free: VecDeque<BytesMut>,
+}
+
+impl BlockPool {
+    pub fn new(max_blocks: usize) -> Rc<RefCell<BlockPool>> {
+        Rc::new(RefCell::new(BlockPool {
+            max_blocks,
+            free: VecDeque::new(),
+        }))
+    }
+
+    fn take(&mut self) -> BytesMut {
+        // Blocks already in the pool were zeroed when first allocated; the
+        // range_set guarantees only written bytes are ever read back out, so
+        // there is no need to re-zero a recycled block.
+        self.free.pop_front().unwrap_or_else(|| BytesMut::zeroed(RBUF_BLOCK_SIZE as usize))
+    }
+
+    fn recycle(&mut self, block: BytesMut) {
+        if self.free.len() < self.max_blocks {
+            self.free.push_back(block);
+        }
+    }
+}
+
 pub struct RecvBuffer {
     start_pos: Seq32,
     max_blocks: u32,
     range_set: RangeSet,
     blocks: VecDeque<BytesMut>,
+    pool: Rc<RefCell<BlockPool>>,
+    final_size: Option<Seq32>,
 }
 
 impl RecvBuffer {
     pub fn with_capacity(size: u32) -> Self {
+        let max_blocks = size / RBUF_BLOCK_SIZE + if size & RBUF_BLOCK_MASK != 0 { 1 } else { 0 };
+        RecvBuffer::with_pool(size, BlockPool::new(max_blocks as usize))
+    }
+
+    /// Like `with_capacity`, but draws its blocks from (and returns them to)
+    /// a pool shared with other `RecvBuffer`s.
+    pub fn with_pool(size: u32, pool: Rc<RefCell<BlockPool>>) -> Self {
         RecvBuffer {
             start_pos: Seq32::from(0),
             max_blocks: size / RBUF_BLOCK_SIZE + if size & RBUF_BLOCK_MASK != 0 { 1 } else { 0 },
             range_set: RangeSet::new(),
             blocks: VecDeque::new(),
+            pool,
+            final_size: None,
+        }
+    }
+
+    /// Records the stream's final offset. All frames must agree on the same
+    /// final size; a conflicting one, or one that is smaller than data
+    /// already received, is rejected.
+    pub fn write_fin(&mut self, pos: Seq32) -> Result<(), &'static str> {
+        if let Some(final_size) = self.final_size {
+            if final_size != pos {
+                return Err("final-size-error");
+            }
+            return Ok(());
+        }
+
+        if self.start_pos > pos
+            || self.range_set.iter().next_back().map_or(false, |(_, &end)| end > pos) {
+            return Err("final-size-error");
+        }
+
+        self.final_size = Some(pos);
+        Ok(())
+    }
+
+    pub fn final_size(&self) -> Option<Seq32> {
+        self.final_size
+    }
+
+    /// Whether the contiguous readable region has reached the final size,
+    /// i.e. the whole stream has been received in order.
+    pub fn is_finished(&self) -> bool {
+        match self.final_size {
+            Some(final_size) => self.start_pos + (self.readable_size() as u32) == final_size,
+            None => false,
         }
     }
 
@@ -66,7 +145,9 @@ impl RecvBuffer {
             if remain >= block_size {
                 self.start_pos += block_size as u32;
                 remain -= block_size;
-                self.blocks.pop_front();
+                if let Some(block) = self.blocks.pop_front() {
+                    self.pool.borrow_mut().recycle(block);
+                }
             } else {
                 self.start_pos += remain as u32;
                 remain = 0;
@@ -83,8 +164,33 @@ impl RecvBuffer {
         Ok(())
     }
 
+    /// Returns a `bytes::Buf` over the whole readable prefix, as a chain of
+    /// block slices, so a caller can hand the full contiguous region to a
+    /// vectored write (or a further `Buf::chain`) without copying it into a
+    /// single buffer first. Does not consume the data; pair with `consume`.
+    pub fn reader(&self) -> ReadableBuf<'_> {
+        ReadableBuf::new(self)
+    }
+
+    /// Reports the first `max_ranges` gaps between `start_pos` and the
+    /// highest position this buffer has seen, i.e. the out-of-order data the
+    /// protocol layer should ask the peer to retransmit.
+    pub fn missing_ranges(&self, start_pos: Seq32, max_ranges: usize) -> Vec<(Seq32, Seq32)> {
+        let to = match self.range_set.iter().next_back() {
+            Some((_, &end)) => end,
+            None => return Vec::new(),
+        };
+        self.range_set.gaps(start_pos, to).take(max_ranges).collect()
+    }
+
     pub fn write(&mut self, pos: Seq32, data: &Bytes) -> Result<(), &'static str> {
         let end = pos + (data.len() as u32);
+        if let Some(final_size) = self.final_size {
+            if end > final_size {
+                return Err("final-size-error");
+            }
+        }
+
         let max_size = RBUF_BLOCK_SIZE * self.max_blocks - (*self.start_pos & RBUF_BLOCK_MASK);
         if data.len() > max_size as usize {
             return Err("size-limit-exceed");
@@ -104,7 +210,7 @@ impl RecvBuffer {
         let required_blocks = (*(end - block_start_pos) >> RBUF_BLOCK_BIT) +
             (if *end & RBUF_BLOCK_MASK == 0 { 0 } else { 1 });
         while self.blocks.len() < required_blocks as usize {
-            self.blocks.push_back(BytesMut::zeroed(RBUF_BLOCK_SIZE as usize));
+            self.blocks.push_back(self.pool.borrow_mut().take());
         }
 
         let mut remain = data.len();
@@ -127,6 +233,62 @@ impl RecvBuffer {
     }
 }
 
+/// A `bytes::Buf` over the readable prefix of a `RecvBuffer`, walking the
+/// underlying block `VecDeque` as a chain of slices instead of copying it
+/// into one contiguous buffer.
+pub struct ReadableBuf<'a> {
+    blocks: &'a VecDeque<BytesMut>,
+    block_idx: usize,
+    block_offset: usize,
+    remaining: usize,
+}
+
+impl<'a> ReadableBuf<'a> {
+    fn new(buf: &'a RecvBuffer) -> Self {
+        ReadableBuf {
+            blocks: &buf.blocks,
+            block_idx: 0,
+            block_offset: (*buf.start_pos & RBUF_BLOCK_MASK) as usize,
+            remaining: buf.readable_size(),
+        }
+    }
+}
+
+impl<'a> Buf for ReadableBuf<'a> {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        if self.remaining == 0 {
+            return &[];
+        }
+        let block = &self.blocks[self.block_idx];
+        let avail = block.len() - self.block_offset;
+        let len = std::cmp::min(avail, self.remaining);
+        &block[self.block_offset..self.block_offset + len]
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        assert!(cnt <= self.remaining, "advance past end of readable region");
+
+        let mut remain = cnt;
+        while remain > 0 {
+            let block = &self.blocks[self.block_idx];
+            let step = std::cmp::min(block.len() - self.block_offset, remain);
+
+            self.block_offset += step;
+            self.remaining -= step;
+            remain -= step;
+
+            if self.block_offset == block.len() {
+                self.block_idx += 1;
+                self.block_offset = 0;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -166,6 +328,96 @@ mod tests {
         assert_eq!(rbuf.consume(1), Err("no-enough-data"));
     }
 
+    #[test]
+    fn recv_buffer_missing_ranges_test() {
+        let mut rbuf = RecvBuffer::with_capacity(13107200);
+
+        assert_eq!(rbuf.write(Seq32::from(0), &Bytes::from("hello")), Ok(()));
+        assert_eq!(rbuf.write(Seq32::from(10), &Bytes::from("world")), Ok(()));
+        assert_eq!(rbuf.write(Seq32::from(20), &Bytes::from("!")), Ok(()));
+
+        assert_eq!(
+            rbuf.missing_ranges(Seq32::from(0), 10),
+            vec![(Seq32::from(5), Seq32::from(10)), (Seq32::from(15), Seq32::from(20))]
+        );
+        assert_eq!(
+            rbuf.missing_ranges(Seq32::from(0), 1),
+            vec![(Seq32::from(5), Seq32::from(10))]
+        );
+
+        assert_eq!(rbuf.write(Seq32::from(5), &Bytes::from("12345")), Ok(()));
+        assert_eq!(
+            rbuf.missing_ranges(Seq32::from(0), 10),
+            vec![(Seq32::from(15), Seq32::from(20))]
+        );
+    }
+
+    #[test]
+    fn recv_buffer_reader_test() {
+        let mut rbuf = RecvBuffer::with_capacity(13107200);
+
+        // span the boundary between the first two 128KB blocks
+        let first = vec![b'a'; RBUF_BLOCK_SIZE as usize - 3];
+        let second = vec![b'b'; 10];
+        assert_eq!(rbuf.write(Seq32::from(0), &Bytes::from(first.clone())), Ok(()));
+        assert_eq!(rbuf.write(Seq32::from(first.len() as u32), &Bytes::from(second.clone())), Ok(()));
+
+        let mut reader = rbuf.reader();
+        assert_eq!(reader.remaining(), first.len() + second.len());
+
+        let mut collected = Vec::new();
+        while reader.has_remaining() {
+            let chunk = reader.chunk();
+            collected.extend_from_slice(chunk);
+            let len = chunk.len();
+            reader.advance(len);
+        }
+        assert_eq!(collected, [first, second].concat());
+
+        // reader() does not consume the underlying buffer
+        assert_eq!(rbuf.readable_size(), collected.len());
+    }
+
+    #[test]
+    fn recv_buffer_shared_pool_test() {
+        let pool = BlockPool::new(4);
+        let mut a = RecvBuffer::with_pool(RBUF_BLOCK_SIZE, pool.clone());
+        let mut b = RecvBuffer::with_pool(RBUF_BLOCK_SIZE, pool.clone());
+
+        assert_eq!(a.write(Seq32::from(0), &Bytes::from(vec![1u8; RBUF_BLOCK_SIZE as usize])), Ok(()));
+        assert_eq!(a.consume(RBUF_BLOCK_SIZE as usize), Ok(()));
+        assert_eq!(pool.borrow().free.len(), 1);
+
+        // `b` should reuse the block `a` just returned to the shared pool.
+        assert_eq!(b.write(Seq32::from(0), &Bytes::from(vec![2u8; RBUF_BLOCK_SIZE as usize])), Ok(()));
+        assert_eq!(pool.borrow().free.len(), 0);
+        assert_eq!(b.peek(), Some(&vec![2u8; RBUF_BLOCK_SIZE as usize][..]));
+    }
+
+    #[test]
+    fn recv_buffer_fin_test() {
+        let mut rbuf = RecvBuffer::with_capacity(13107200);
+
+        assert_eq!(rbuf.write(Seq32::from(0), &Bytes::from("hello")), Ok(()));
+        assert_eq!(rbuf.write_fin(Seq32::from(3)), Err("final-size-error"));
+        assert_eq!(rbuf.write_fin(Seq32::from(5)), Ok(()));
+        assert_eq!(rbuf.final_size(), Some(Seq32::from(5)));
+        assert_eq!(rbuf.write_fin(Seq32::from(5)), Ok(()));
+        assert_eq!(rbuf.write_fin(Seq32::from(6)), Err("final-size-error"));
+        assert_eq!(rbuf.write(Seq32::from(5), &Bytes::from("x")), Err("final-size-error"));
+
+        assert_eq!(rbuf.is_finished(), true);
+        assert_eq!(rbuf.consume(5), Ok(()));
+        assert_eq!(rbuf.is_finished(), true);
+
+        let mut rbuf2 = RecvBuffer::with_capacity(13107200);
+        assert_eq!(rbuf2.write_fin(Seq32::from(10)), Ok(()));
+        assert_eq!(rbuf2.write(Seq32::from(0), &Bytes::from("hello")), Ok(()));
+        assert_eq!(rbuf2.is_finished(), false);
+        assert_eq!(rbuf2.write(Seq32::from(5), &Bytes::from("world")), Ok(()));
+        assert_eq!(rbuf2.is_finished(), true);
+    }
+
     #[test]
     fn recv_buffer_5gb_read_write_test() {
         let mut rng = rand::thread_rng();
@@ -0,0 +1,216 @@
+use std::time::{Duration, Instant};
+
+use crate::common::seq32::Seq32;
+use crate::inflight::PacketEntry;
+
+/// A single delivery-rate observation, produced whenever an in-flight packet
+/// is acknowledged. Congestion controllers (e.g. a BBR-style one) consume
+/// these to drive their bandwidth/rtt estimates.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct RateSample {
+    pub(crate) delivery_rate: f64,
+    pub(crate) rtt: Duration,
+    pub(crate) is_app_limited: bool,
+}
+
+/// Turns per-packet delivery bookkeeping (`PacketEntry`) into connection-level
+/// `RateSample`s, mirroring the delivery-rate tracking used by BBR.
+pub(crate) struct DeliveryRateEstimator {
+    // C.delivered: monotonic count of bytes acked on this connection. A
+    // u64 so it doesn't overflow on long-lived, multi-gigabyte streams.
+    delivered: u64,
+    // C.delivered_time: Instant of the most recent ack-driven update.
+    delivered_time: Option<Instant>,
+    // First send time of the current send train; reset whenever the pipe
+    // goes idle so the next train starts a fresh measurement window.
+    first_tx_time: Option<Instant>,
+    // Highest sequence sent while the pipe was known to be app-limited.
+    app_limited: Option<Seq32>,
+    last_sample: Option<RateSample>,
+}
+
+impl DeliveryRateEstimator {
+    pub(crate) fn new() -> Self {
+        DeliveryRateEstimator {
+            delivered: 0,
+            // Seed delivered_time to connection start rather than leaving it
+            // unset, so the very first packet sent already has a baseline
+            // to measure its ack_elapsed interval against.
+            delivered_time: Some(Instant::now()),
+            first_tx_time: None,
+            app_limited: None,
+            last_sample: None,
+        }
+    }
+
+    /// Marks the connection as application-limited up to `seq`: packets sent
+    /// at or before this sequence were not limited by the congestion window.
+    pub(crate) fn mark_app_limited(&mut self, seq: Seq32) {
+        self.app_limited = Some(seq);
+    }
+
+    /// Stamps a packet about to be transmitted with the delivery-rate state
+    /// needed to turn its eventual ack into a sample. `idle` indicates the
+    /// pipe had no bytes in flight immediately before this send.
+    pub(crate) fn on_packet_sent(&mut self, pkt: &mut PacketEntry, now: Instant, idle: bool) {
+        if idle || self.first_tx_time.is_none() {
+            self.first_tx_time = Some(now);
+        }
+
+        pkt.delivered = self.delivered;
+        pkt.delivered_time = self.delivered_time;
+        pkt.first_tx_time = self.first_tx_time;
+        pkt.is_app_limited = self.app_limited.map_or(false, |limit| pkt.seq <= limit);
+    }
+
+    /// Folds the ack of `pkt` (which carried `acked_bytes` bytes) into the
+    /// connection's delivered-bytes counter and returns the resulting
+    /// `RateSample`, if the packet carries enough history to compute one.
+    /// Returns `None` when the measured interval is zero, or when an
+    /// app-limited sample does not beat the last accepted rate.
+    pub(crate) fn on_ack(
+        &mut self,
+        pkt: &PacketEntry,
+        acked_bytes: u64,
+        now: Instant,
+    ) -> Option<RateSample> {
+        self.delivered = self.delivered.wrapping_add(acked_bytes);
+        self.delivered_time = Some(now);
+
+        let prior_delivered_time = pkt.delivered_time?;
+        let first_tx_time = pkt.first_tx_time?;
+
+        // Take the max of the ack-side and send-side intervals so ACK
+        // compression (several acks arriving back-to-back) doesn't make the
+        // sample look faster than the pipe actually is.
+        let ack_elapsed = now.saturating_duration_since(prior_delivered_time);
+        let send_elapsed = pkt.sent_time.saturating_duration_since(first_tx_time);
+        let interval = std::cmp::max(ack_elapsed, send_elapsed);
+        if interval.is_zero() {
+            return None;
+        }
+
+        let delivered_interval = self.delivered.wrapping_sub(pkt.delivered);
+        let sample = RateSample {
+            delivery_rate: delivered_interval as f64 / interval.as_secs_f64(),
+            rtt: now.saturating_duration_since(pkt.sent_time),
+            is_app_limited: pkt.is_app_limited,
+        };
+
+        // A sample taken while app-limited underestimates the true capacity
+        // of the pipe, so only let it through if it still beats what we
+        // already believe the rate to be.
+        if sample.is_app_limited {
+            if let Some(last) = self.last_sample {
+                if sample.delivery_rate <= last.delivery_rate {
+                    return None;
+                }
+            }
+        }
+
+        self.last_sample = Some(sample);
+        Some(sample)
+    }
+
+    /// The most recent sample accepted by `on_ack`, if any.
+    pub(crate) fn sample(&self) -> Option<RateSample> {
+        self.last_sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(delivered: u64, sent_time: Instant, delivered_time: Option<Instant>, first_tx_time: Option<Instant>, is_app_limited: bool) -> PacketEntry {
+        PacketEntry {
+            delivered,
+            flow: 0,
+            seq: Seq32::from(0),
+            is_app_limited,
+            retrans_round: 0,
+            sent_time,
+            rto_time: sent_time,
+            delivered_time,
+            first_tx_time,
+        }
+    }
+
+    #[test]
+    fn delivery_rate_sample_math_test() {
+        let t0 = Instant::now();
+        let mut est = DeliveryRateEstimator {
+            delivered: 0,
+            delivered_time: Some(t0),
+            first_tx_time: Some(t0),
+            app_limited: None,
+            last_sample: None,
+        };
+
+        // Stamped when sent: baseline delivered is 0, train started at t0.
+        let pkt = packet(0, t0, Some(t0), Some(t0), false);
+
+        // Acked 100ms later having delivered 1000 bytes: (1000 - 0) / 0.1s.
+        let t1 = t0 + Duration::from_millis(100);
+        let sample = est.on_ack(&pkt, 1000, t1).expect("packet carries full history");
+        assert!((sample.delivery_rate - 10_000.0).abs() < 1.0);
+        assert_eq!(sample.rtt, Duration::from_millis(100));
+        assert_eq!(sample.is_app_limited, false);
+    }
+
+    #[test]
+    fn delivery_rate_ack_compression_guard_test() {
+        let t0 = Instant::now();
+        let mut est = DeliveryRateEstimator {
+            delivered: 0,
+            delivered_time: Some(t0),
+            first_tx_time: Some(t0),
+            app_limited: None,
+            last_sample: None,
+        };
+
+        // Packet was sent 150ms into the train but its ack arrives only
+        // 10ms after the previous update landed: a burst of compressed
+        // acks. The 150ms send-side interval must win the max(), or the
+        // sample would look 15x faster than the pipe actually is.
+        let sent_time = t0 + Duration::from_millis(150);
+        let prior_delivered_time = t0 + Duration::from_millis(190);
+        let pkt = packet(0, sent_time, Some(prior_delivered_time), Some(t0), false);
+
+        let ack_time = prior_delivered_time + Duration::from_millis(10);
+        let sample = est.on_ack(&pkt, 3000, ack_time).expect("packet carries full history");
+        assert!((sample.delivery_rate - 20_000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn delivery_rate_app_limited_discard_test() {
+        let t0 = Instant::now();
+        let last = RateSample {
+            delivery_rate: 1000.0,
+            rtt: Duration::from_millis(50),
+            is_app_limited: false,
+        };
+        let mut est = DeliveryRateEstimator {
+            delivered: 1000,
+            delivered_time: Some(t0),
+            first_tx_time: Some(t0),
+            app_limited: None,
+            last_sample: Some(last),
+        };
+
+        // App-limited sample whose rate doesn't beat what we already
+        // believe the pipe can do is discarded, leaving last_sample as-is.
+        let t1 = t0 + Duration::from_millis(100);
+        let pkt = packet(1000, t0, Some(t0), Some(t0), true);
+        assert_eq!(est.on_ack(&pkt, 100, t1), None);
+        assert_eq!(est.sample(), Some(last));
+
+        // A later app-limited sample that does beat it is accepted and
+        // replaces last_sample.
+        let t2 = t1 + Duration::from_millis(100);
+        let pkt = packet(1100, t1, Some(t1), Some(t0), true);
+        let sample = est.on_ack(&pkt, 50_000, t2).expect("faster app-limited sample should be accepted");
+        assert!(sample.delivery_rate > last.delivery_rate);
+        assert_eq!(est.sample(), Some(sample));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use bytes::Bytes;
+
+use crate::common::seq32::Seq32;
+use crate::connection::send_buffer::SendBuffer;
+
+struct Flow {
+    buffer: SendBuffer,
+    priority: u8,
+    incremental: bool,
+}
+
+/// Schedules transmission across several per-flow `SendBuffer`s, adapting
+/// QUIC's stream-prioritization model: flows are served lowest-urgency-value
+/// first, incremental flows at the same urgency are round-robined for
+/// fairness, and a non-incremental flow is drained fully before its peers at
+/// the same urgency get a turn.
+pub struct FlowScheduler {
+    limit: usize,
+    mss: usize,
+    flows: BTreeMap<u32, Flow>,
+    // last flow id served at each urgency level, used to pick up round-robin
+    // where it left off
+    cursor: BTreeMap<u8, u32>,
+}
+
+impl FlowScheduler {
+    pub fn new(limit: usize, mss: usize) -> Self {
+        FlowScheduler {
+            limit,
+            mss,
+            flows: BTreeMap::new(),
+            cursor: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_flow(&mut self, id: u32, priority: u8, incremental: bool) {
+        self.flows.insert(id, Flow {
+            buffer: SendBuffer::new(self.limit, self.mss),
+            priority,
+            incremental,
+        });
+    }
+
+    pub fn push_back(&mut self, flow: u32, data: &Bytes) -> bool {
+        match self.flows.get_mut(&flow) {
+            Some(f) => f.buffer.push_back(data),
+            None => false,
+        }
+    }
+
+    /// Picks the next segment to transmit across all flows, by urgency then
+    /// fairness, and returns which flow it came from.
+    pub fn pop_unsent(&mut self) -> Option<(u32, Seq32, &[u8])> {
+        let priority = self.flows.values()
+            .filter(|f| f.buffer.has_pending())
+            .map(|f| f.priority)
+            .min()?;
+
+        let mut ids: Vec<u32> = self.flows.iter()
+            .filter(|(_, f)| f.priority == priority && f.buffer.has_pending())
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+
+        let last = self.cursor.get(&priority).copied();
+        let selected = match last {
+            // a non-incremental flow keeps its turn until it runs dry
+            Some(last_id) if ids.contains(&last_id) && !self.flows[&last_id].incremental => last_id,
+            Some(last_id) => {
+                let next_idx = ids.iter().position(|&id| id > last_id).unwrap_or(0);
+                ids[next_idx]
+            }
+            None => ids[0],
+        };
+
+        self.cursor.insert(priority, selected);
+
+        let flow = self.flows.get_mut(&selected).unwrap();
+        flow.buffer.pop_unsent().map(|(pos, data)| (selected, pos, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduler_strict_priority_test() {
+        let mut sched = FlowScheduler::new(1000, 10);
+        sched.add_flow(0, 1, false);
+        sched.add_flow(1, 0, false);
+
+        assert_eq!(sched.push_back(0, &Bytes::from("lowpri")), true);
+        assert_eq!(sched.push_back(1, &Bytes::from("highpri")), true);
+
+        // flow 1 (urgency 0) must be fully drained before flow 0 gets a turn
+        assert_eq!(sched.pop_unsent(), Some((1, Seq32::from(0), &Bytes::from("highpri") as &[u8])));
+        assert_eq!(sched.pop_unsent(), Some((0, Seq32::from(0), &Bytes::from("lowpri") as &[u8])));
+        assert_eq!(sched.pop_unsent(), None);
+    }
+
+    #[test]
+    fn scheduler_incremental_round_robin_test() {
+        let mut sched = FlowScheduler::new(1000, 10);
+        sched.add_flow(0, 0, true);
+        sched.add_flow(1, 0, true);
+
+        assert_eq!(sched.push_back(0, &Bytes::from("aaaaaaaaaaaaaaaaaaaa")), true);
+        assert_eq!(sched.push_back(1, &Bytes::from("bbbbbbbbbbbbbbbbbbbb")), true);
+
+        assert_eq!(sched.pop_unsent(), Some((0, Seq32::from(0), &Bytes::from("aaaaaaaaaa") as &[u8])));
+        assert_eq!(sched.pop_unsent(), Some((1, Seq32::from(0), &Bytes::from("bbbbbbbbbb") as &[u8])));
+        assert_eq!(sched.pop_unsent(), Some((0, Seq32::from(10), &Bytes::from("aaaaaaaaaa") as &[u8])));
+        assert_eq!(sched.pop_unsent(), Some((1, Seq32::from(10), &Bytes::from("bbbbbbbbbb") as &[u8])));
+        assert_eq!(sched.pop_unsent(), None);
+    }
+
+    #[test]
+    fn scheduler_non_incremental_drains_before_next_test() {
+        let mut sched = FlowScheduler::new(1000, 10);
+        sched.add_flow(0, 0, false);
+        sched.add_flow(1, 0, true);
+
+        assert_eq!(sched.push_back(0, &Bytes::from("aaaaaaaaaaaaaaaaaaaa")), true);
+        assert_eq!(sched.push_back(1, &Bytes::from("bbbbbbbbbb")), true);
+
+        // flow 0 is non-incremental: both its segments go out before flow 1 gets one
+        assert_eq!(sched.pop_unsent(), Some((0, Seq32::from(0), &Bytes::from("aaaaaaaaaa") as &[u8])));
+        assert_eq!(sched.pop_unsent(), Some((0, Seq32::from(10), &Bytes::from("aaaaaaaaaa") as &[u8])));
+        assert_eq!(sched.pop_unsent(), Some((1, Seq32::from(0), &Bytes::from("bbbbbbbbbb") as &[u8])));
+        assert_eq!(sched.pop_unsent(), None);
+    }
+}
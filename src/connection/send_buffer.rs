@@ -1,8 +1,12 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
 use bytes::{Bytes, BytesMut, BufMut};
 use std::ops::Bound::{Included, Excluded};
 
+use crate::common::range_set::RangeSet;
 use crate::common::seq32::Seq32;
+use crate::congestion::delivery_rate::{DeliveryRateEstimator, RateSample};
+use crate::inflight::PacketEntry;
 
 pub struct SendBuffer {
     queue: BTreeMap<Seq32, BytesMut>,
@@ -11,6 +15,17 @@ pub struct SendBuffer {
     size: usize,
     limit: usize,
     mss: usize,
+    fin: Option<Seq32>,
+    // Segments handed out by `pop_unsent` at least once, keyed by their
+    // start sequence, so a SACK-driven fast-retransmit can find their
+    // sent-time/retrans-round bookkeeping.
+    inflight: BTreeMap<Seq32, PacketEntry>,
+    // Segments `mark_lost` flagged for resend, served by `pop_unsent` ahead
+    // of any fresh, never-sent data.
+    retransmit_queue: VecDeque<Seq32>,
+    // Turns the sent/acked timestamps on `inflight` entries into delivery
+    // rate samples for congestion control.
+    delivery_rate: DeliveryRateEstimator,
 }
 
 impl SendBuffer {
@@ -22,6 +37,10 @@ impl SendBuffer {
             size: 0,
             limit: limit,
             mss: mss,
+            fin: None,
+            inflight: BTreeMap::new(),
+            retransmit_queue: VecDeque::new(),
+            delivery_rate: DeliveryRateEstimator::new(),
         }
     }
 
@@ -29,8 +48,27 @@ impl SendBuffer {
         self.limit - self.size
     }
 
+    /// Marks the end of the stream at the current enqueue offset: no more
+    /// data may be pushed after this point.
+    pub fn finish(&mut self) {
+        self.fin = Some(self.enqueue);
+    }
+
+    pub fn final_size(&self) -> Option<Seq32> {
+        self.fin
+    }
+
+    /// Whether every byte before the final offset has been
+    /// acked, i.e. the peer can be told the stream is fully drained.
+    pub fn is_finished(&self) -> bool {
+        match self.fin {
+            Some(fin_pos) => self.queue.is_empty() && self.unsent >= fin_pos,
+            None => false,
+        }
+    }
+
     pub fn push_back(&mut self, data: &Bytes) -> bool {
-        if self.size + data.len() > self.limit {
+        if self.fin.is_some() || self.size + data.len() > self.limit {
             return false;
         }
         // if last segment was not full, extend last segment first
@@ -64,8 +102,33 @@ impl SendBuffer {
         true
     }
 
+    /// Whether a subsequent `pop_unsent` would have something to hand out.
+    pub fn has_pending(&self) -> bool {
+        self.unsent < self.enqueue
+            || self.retransmit_queue.iter().any(|pos| self.queue.contains_key(pos))
+    }
+
     pub fn pop_unsent(&mut self) -> Option<(Seq32, &[u8])> {
+        // serve flagged retransmissions before any never-sent data
+        while let Some(&pos) = self.retransmit_queue.front() {
+            self.retransmit_queue.pop_front();
+            if !self.queue.contains_key(&pos) {
+                // already fully acked since it was marked lost
+                continue;
+            }
+            let now = Instant::now();
+            let idle = self.inflight.is_empty();
+            if let Some(entry) = self.inflight.get_mut(&pos) {
+                entry.sent_time = now;
+                self.delivery_rate.on_packet_sent(entry, now, idle);
+            }
+            return self.queue.get(&pos).map(|data| (pos, data as &[u8]));
+        }
+
         if self.unsent >= self.enqueue {
+            // nothing left to send: any packet already in flight was sent
+            // while the pipe was application-limited, not congestion-limited
+            self.delivery_rate.mark_app_limited(self.enqueue);
             return None;
         }
 
@@ -73,30 +136,84 @@ impl SendBuffer {
         let pos = self.unsent;
         self.unsent += data.len() as u32;
 
+        let now = Instant::now();
+        let idle = self.inflight.is_empty();
+        let mut entry = PacketEntry {
+            delivered: 0,
+            flow: 0,
+            seq: pos,
+            is_app_limited: false,
+            retrans_round: 0,
+            sent_time: now,
+            rto_time: now,
+            delivered_time: None,
+            first_tx_time: None,
+        };
+        self.delivery_rate.on_packet_sent(&mut entry, now, idle);
+        self.inflight.insert(pos, entry);
+
         Some((pos, data))
     }
 
+    /// The most recent delivery-rate sample derived from acked segments, for
+    /// congestion control to consume.
+    pub fn delivery_rate(&self) -> Option<RateSample> {
+        self.delivery_rate.sample()
+    }
+
     pub fn get(&self, pos: Seq32) -> Option<&[u8]> {
         self.queue.get(&pos).map(|x| x as &[u8])
     }
 
+    /// Flags the segment starting at `pos` for retransmission ahead of fresh
+    /// data, bumping its retransmission round so RTO backoff can track it.
+    pub fn mark_lost(&mut self, pos: Seq32) {
+        if let Some(entry) = self.inflight.get_mut(&pos) {
+            entry.retrans_round += 1;
+        }
+        if self.queue.contains_key(&pos) && !self.retransmit_queue.contains(&pos) {
+            self.retransmit_queue.push_back(pos);
+        }
+    }
+
+    /// Given the ranges the peer has selectively acknowledged, yields the
+    /// still in-flight segments below the highest acked sequence: the gaps a
+    /// SACK-capable receiver is telling us to fast-retransmit.
+    pub fn needs_retransmit<'a>(&'a self, acked: &'a RangeSet) -> impl Iterator<Item = (Seq32, &'a [u8])> {
+        let unsent = self.unsent;
+        let highest = acked.iter().next_back().map(|(_, &end)| end);
+        self.queue.iter().filter_map(move |(&pos, data)| {
+            match highest {
+                Some(highest) if pos < highest && pos < unsent && !acked.contains(pos) => {
+                    Some((pos, data as &[u8]))
+                }
+                _ => None,
+            }
+        })
+    }
+
     pub fn ack(&mut self, mut start: Seq32, end: Seq32) -> usize {
         if end <= start || start >= self.unsent || end > self.unsent {
             return 0;
         }
 
+        let now = Instant::now();
         let mut acked = 0;
         if let Some((&orig_start, _)) = self.queue.iter().next() {
             while let Some((&pos, _)) = self.queue.range((Included(start), Excluded(end))).next() {
+                let len = self.queue.get(&pos).unwrap().len() as u64;
                 self.queue.remove(&pos);
+                if let Some(entry) = self.inflight.remove(&pos) {
+                    self.delivery_rate.on_ack(&entry, len, now);
+                }
                 start = pos + 1;
                 acked += 1;
-            }   
+            }
             if let Some((&new_start, _)) = self.queue.iter().next() {
                 self.size -= *(new_start - orig_start) as usize;
             } else {
                 self.size = 0;
-            }         
+            }
         }
         acked
     }
@@ -135,6 +252,60 @@ mod tests {
         assert_eq!(sbuf.writable_size(), 100);
     }
 
+    #[test]
+    fn send_buffer_fin_test() {
+        let mut sbuf = SendBuffer::new(100, 10);
+        assert_eq!(sbuf.push_back(&Bytes::from("12345")), true);
+        assert_eq!(sbuf.final_size(), None);
+
+        sbuf.finish();
+        assert_eq!(sbuf.final_size(), Some(Seq32::from(5)));
+        assert_eq!(sbuf.push_back(&Bytes::from("more")), false);
+
+        assert_eq!(sbuf.is_finished(), false);
+        assert_eq!(sbuf.pop_unsent(), Some((Seq32::from(0), &Bytes::from("12345") as &[u8])));
+        assert_eq!(sbuf.is_finished(), false);
+        assert_eq!(sbuf.ack(Seq32::from(0), Seq32::from(5)), 1);
+        assert_eq!(sbuf.is_finished(), true);
+    }
+
+    #[test]
+    fn send_buffer_retransmit_test() {
+        let mut sbuf = SendBuffer::new(100, 10);
+        assert_eq!(sbuf.push_back(&Bytes::from("0123456789ABCDEFGHIJ")), true);
+        assert_eq!(sbuf.pop_unsent(), Some((Seq32::from(0), &Bytes::from("0123456789") as &[u8])));
+        assert_eq!(sbuf.pop_unsent(), Some((Seq32::from(10), &Bytes::from("ABCDEFGHIJ") as &[u8])));
+        assert_eq!(sbuf.pop_unsent(), None);
+
+        // peer SACKed the second segment only, leaving a gap below it
+        let mut acked = RangeSet::new();
+        acked.insert(Seq32::from(10), Seq32::from(20));
+        let gaps: Vec<_> = sbuf.needs_retransmit(&acked).collect();
+        assert_eq!(gaps, vec![(Seq32::from(0), &Bytes::from("0123456789") as &[u8])]);
+
+        sbuf.mark_lost(Seq32::from(0));
+        assert_eq!(sbuf.pop_unsent(), Some((Seq32::from(0), &Bytes::from("0123456789") as &[u8])));
+        assert_eq!(sbuf.pop_unsent(), None);
+
+        assert_eq!(sbuf.ack(Seq32::from(0), Seq32::from(20)), 2);
+        assert_eq!(sbuf.needs_retransmit(&acked).collect::<Vec<_>>(), vec![]);
+    }
+
+    #[test]
+    fn send_buffer_delivery_rate_test() {
+        let mut sbuf = SendBuffer::new(100, 10);
+        assert_eq!(sbuf.delivery_rate().is_none(), true);
+
+        assert_eq!(sbuf.push_back(&Bytes::from("0123456789")), true);
+        assert_eq!(sbuf.pop_unsent(), Some((Seq32::from(0), &Bytes::from("0123456789") as &[u8])));
+        assert_eq!(sbuf.ack(Seq32::from(0), Seq32::from(10)), 1);
+
+        let sample = sbuf.delivery_rate().expect("ack should produce a delivery-rate sample");
+        assert!(sample.delivery_rate >= 0.0);
+        assert!(sample.rtt >= std::time::Duration::from_secs(0));
+        assert_eq!(sample.is_app_limited, false);
+    }
+
     #[test]
     fn send_buffer_5gb_read_write_test() {
         let mut rng = rand::thread_rng();
@@ -1,13 +1,15 @@
 use std::time::Instant;
 
-struct PacketEntry {
-    delivered: u32,
-    flow: u32,
-    seq: Seq32,
-    is_app_limited: bool,
-    retrans_round: u32,
-    sent_time: Instant,
-    rto_time: Instant ,
-    delivered_time: Option<Instant>,
-    first_tx_time: Option<Instant>,
+use crate::common::seq32::Seq32;
+
+pub(crate) struct PacketEntry {
+    pub(crate) delivered: u64,
+    pub(crate) flow: u32,
+    pub(crate) seq: Seq32,
+    pub(crate) is_app_limited: bool,
+    pub(crate) retrans_round: u32,
+    pub(crate) sent_time: Instant,
+    pub(crate) rto_time: Instant ,
+    pub(crate) delivered_time: Option<Instant>,
+    pub(crate) first_tx_time: Option<Instant>,
 }